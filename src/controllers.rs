@@ -1,48 +1,115 @@
 use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
 use chrono::Utc;
-use csv;
 use hex;
 use rand::prelude::*;
 use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json;
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fs::{File, OpenOptions},
-    path::Path,
-    sync::{Arc, Mutex},
+    sync::Arc,
 };
 
-use crate::models::{Code, Quiz, QuizQuestion, UserId, UserRecord, UserState, Wheel};
+use crate::models::{Code, Config, Quiz, QuizQuestion, UserId, UserRecord, UserState, Wheel};
+use crate::store::UserStore;
+
+fn build_quiz_map<'a>(quiz: impl Iterator<Item = &'a Quiz>) -> Result<BTreeMap<String, Quiz>> {
+    let mut map = BTreeMap::new();
+    for quiz in quiz {
+        if map.insert(quiz.name.clone(), quiz.clone()).is_some() {
+            return Err(anyhow!("duplicate quiz name: {}", quiz.name));
+        }
+    }
+    Ok(map)
+}
+
+fn build_code_map<'a>(codes: impl Iterator<Item = &'a Code>) -> Result<BTreeMap<String, Code>> {
+    let mut map = BTreeMap::new();
+    for code in codes {
+        if map.insert(code.code.clone(), code.clone()).is_some() {
+            return Err(anyhow!("duplicate code: {}", code.code));
+        }
+    }
+    Ok(map)
+}
+
+fn build_wheel_set<'a>(wheel: impl Iterator<Item = &'a Wheel>) -> Result<BTreeSet<String>> {
+    let mut set = BTreeSet::new();
+    for wheel in wheel {
+        if !set.insert(wheel.name.clone()) {
+            return Err(anyhow!("duplicate wheel name: {}", wheel.name));
+        }
+    }
+    Ok(set)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct JwtHeader {
+    alg: String,
+    typ: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct JwtClaims {
+    sub: String,
+    answers: BTreeMap<String, Vec<bool>>,
+    wheels: BTreeMap<String, u8>,
+    iat: i64,
+    exp: i64,
+}
 
 #[derive(Clone, Debug)]
 pub struct QuizController {
-    secret_key: Arc<hmac::Key>,
-    codes: Arc<BTreeMap<String, Code>>,
-    quiz: Arc<BTreeMap<String, Quiz>>,
-    wheel: Arc<BTreeSet<String>>,
-    user_writer: UserWriter,
+    primary_key_id: Arc<String>,
+    keys: Arc<BTreeMap<String, hmac::Key>>,
+    codes: Arc<ArcSwap<BTreeMap<String, Code>>>,
+    quiz: Arc<ArcSwap<BTreeMap<String, Quiz>>>,
+    wheel: Arc<ArcSwap<BTreeSet<String>>>,
+    user_store: Arc<dyn UserStore>,
+    token_ttl_seconds: i64,
+    legacy_tokens_enabled: bool,
 }
 
 impl QuizController {
     pub fn new<'a>(
-        secret_key: hmac::Key,
+        primary_key_id: String,
+        keys: BTreeMap<String, hmac::Key>,
         quiz: impl Iterator<Item = &'a Quiz>,
         codes: impl Iterator<Item = &'a Code>,
         wheel: impl Iterator<Item = &'a Wheel>,
-        user_writer: UserWriter,
-    ) -> QuizController {
-        let quiz = quiz.map(|quiz| (quiz.name.clone(), quiz.clone())).collect();
-        let codes = codes
-            .map(|code| (code.code.clone(), code.clone()))
-            .collect();
-        let wheel = wheel.map(|wheel| wheel.name.clone()).collect();
-
-        QuizController {
-            secret_key: Arc::new(secret_key),
-            quiz: Arc::new(quiz),
-            codes: Arc::new(codes),
-            wheel: Arc::new(wheel),
-            user_writer,
-        }
+        user_store: Arc<dyn UserStore>,
+        token_ttl_seconds: i64,
+        legacy_tokens_enabled: bool,
+    ) -> Result<QuizController> {
+        let quiz = build_quiz_map(quiz)?;
+        let codes = build_code_map(codes)?;
+        let wheel = build_wheel_set(wheel)?;
+
+        Ok(QuizController {
+            primary_key_id: Arc::new(primary_key_id),
+            keys: Arc::new(keys),
+            quiz: Arc::new(ArcSwap::new(Arc::new(quiz))),
+            codes: Arc::new(ArcSwap::new(Arc::new(codes))),
+            wheel: Arc::new(ArcSwap::new(Arc::new(wheel))),
+            user_store,
+            token_ttl_seconds,
+            legacy_tokens_enabled,
+        })
+    }
+
+    pub fn reload(&self, config: &Config) -> Result<()> {
+        let quiz = build_quiz_map(config.quiz.iter())?;
+        let codes = build_code_map(config.code.iter())?;
+        let wheel = build_wheel_set(config.wheel.iter())?;
+
+        self.quiz.store(Arc::new(quiz));
+        self.codes.store(Arc::new(codes));
+        self.wheel.store(Arc::new(wheel));
+
+        Ok(())
     }
 
     pub fn create_user(&self) -> UserState {
@@ -60,6 +127,37 @@ impl QuizController {
     }
 
     pub fn decode_user(&self, token: &str) -> Result<UserState> {
+        if token.matches('.').count() == 2 {
+            self.decode_user_jwt(token)
+        } else if self.legacy_tokens_enabled {
+            self.decode_user_legacy(token)
+        } else {
+            Err(anyhow!("legacy tokens are disabled"))
+        }
+    }
+
+    fn verify_signature(&self, kid: Option<&str>, signing_input: &[u8], signature: &[u8]) -> Result<()> {
+        match kid {
+            Some(kid) => {
+                let key = self
+                    .keys
+                    .get(kid)
+                    .ok_or_else(|| anyhow!("unknown key id: {}", kid))?;
+
+                hmac::verify(key, signing_input, signature)
+                    .map_err(|_err| anyhow!("invalid signature"))
+            }
+            // Legacy tokens carry no kid, so fall back to trying every known key.
+            None => self
+                .keys
+                .values()
+                .find(|key| hmac::verify(key, signing_input, signature).is_ok())
+                .map(|_key| ())
+                .ok_or_else(|| anyhow!("invalid signature")),
+        }
+    }
+
+    fn decode_user_legacy(&self, token: &str) -> Result<UserState> {
         let mut parts = token.splitn(2, ':');
         let user_state = parts
             .next()
@@ -71,51 +169,107 @@ impl QuizController {
             .ok_or_else(|| anyhow!("bad authorization token"))?;
         let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)?;
 
-        hmac::verify(&self.secret_key, &user_state, &signature)
-            .map_err(|_err| anyhow!("invalid signature"))?;
+        self.verify_signature(None, &user_state, &signature)?;
 
         let state = bincode::deserialize(&user_state)?;
         Ok(state)
     }
 
+    fn decode_user_jwt(&self, token: &str) -> Result<UserState> {
+        let mut parts = token.splitn(3, '.');
+        let header = parts
+            .next()
+            .ok_or_else(|| anyhow!("bad authorization token"))?;
+        let claims = parts
+            .next()
+            .ok_or_else(|| anyhow!("bad authorization token"))?;
+        let signature = parts
+            .next()
+            .ok_or_else(|| anyhow!("bad authorization token"))?;
+
+        let signing_input = format!("{}.{}", header, claims);
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)?;
+
+        let decoded_header = base64::decode_config(header, base64::URL_SAFE_NO_PAD)?;
+        let decoded_header: JwtHeader = serde_json::from_slice(&decoded_header)?;
+
+        if decoded_header.alg != "HS256" {
+            return Err(anyhow!("unsupported alg: {}", decoded_header.alg));
+        }
+
+        self.verify_signature(
+            decoded_header.kid.as_deref(),
+            signing_input.as_bytes(),
+            &signature,
+        )?;
+
+        let claims = base64::decode_config(claims, base64::URL_SAFE_NO_PAD)?;
+        let claims: JwtClaims = serde_json::from_slice(&claims)?;
+
+        if claims.exp < Utc::now().timestamp() {
+            return Err(anyhow!("token expired"));
+        }
+
+        let mut id = [0u8; 16];
+        hex::decode_to_slice(&claims.sub, &mut id)?;
+
+        Ok(UserState {
+            id: UserId(id),
+            answers: claims.answers,
+            wheels: claims.wheels,
+        })
+    }
+
     pub fn encode_user(&self, user_state: &UserState) -> Result<String> {
-        let user_state = bincode::serialize(&user_state)?;
+        let header = JwtHeader {
+            alg: "HS256".into(),
+            typ: "JWT".into(),
+            kid: Some((*self.primary_key_id).clone()),
+        };
+        let header = base64::encode_config(serde_json::to_vec(&header)?, base64::URL_SAFE_NO_PAD);
+
+        let now = Utc::now().timestamp();
+        let claims = JwtClaims {
+            sub: hex::encode(user_state.id.0),
+            answers: user_state.answers.clone(),
+            wheels: user_state.wheels.clone(),
+            iat: now,
+            exp: now + self.token_ttl_seconds,
+        };
+        let claims = base64::encode_config(serde_json::to_vec(&claims)?, base64::URL_SAFE_NO_PAD);
 
-        let signature = hmac::sign(&self.secret_key, &user_state);
+        let signing_key = self
+            .keys
+            .get(self.primary_key_id.as_str())
+            .ok_or_else(|| anyhow!("primary signing key is not loaded"))?;
 
-        let token = format!(
-            "{}:{}",
-            base64::encode_config(user_state, base64::URL_SAFE_NO_PAD),
-            base64::encode_config(signature, base64::URL_SAFE_NO_PAD),
-        );
+        let signing_input = format!("{}.{}", header, claims);
+        let signature = hmac::sign(signing_key, signing_input.as_bytes());
+        let signature = base64::encode_config(signature, base64::URL_SAFE_NO_PAD);
 
-        Ok(token)
+        Ok(format!("{}.{}", signing_input, signature))
     }
 
-    pub fn next_question<'a>(
-        &'a self,
-        quiz_name: &str,
-        user_state: &UserState,
-    ) -> Option<&'a QuizQuestion> {
-        let quiz = self.quiz.get(quiz_name);
+    pub fn next_question(&self, quiz_name: &str, user_state: &UserState) -> Option<QuizQuestion> {
+        let quiz = self.quiz.load();
+        let quiz = quiz.get(quiz_name);
         let answers = user_state.answers.get(quiz_name);
 
         match quiz {
             None => None,
             Some(quiz) => {
                 let index = answers.map(|answers| answers.len()).unwrap_or(0);
-                let question = quiz.questions.get(index);
-                question
+                quiz.questions.get(index).cloned()
             }
         }
     }
 
-    pub fn answer_question<'a>(
-        &'a self,
+    pub fn answer_question(
+        &self,
         quiz_name: &str,
         user_state: &mut UserState,
         answer: &str,
-    ) -> Option<(bool, &'a QuizQuestion)> {
+    ) -> Option<(bool, QuizQuestion)> {
         let next_question = self.next_question(quiz_name, user_state);
 
         match next_question {
@@ -143,7 +297,8 @@ impl QuizController {
     pub fn spin_wheel(&self, wheel: &str, user_state: &mut UserState) -> Option<u32> {
         const CHOICES: &[(u8, u8)] = &[(20, 3), (40, 2), (60, 1)];
 
-        let wheel = self.wheel.get(wheel)?;
+        let wheel_set = self.wheel.load();
+        let wheel = wheel_set.get(wheel)?;
         match user_state.wheels.get(wheel) {
             None => {
                 let mut rng = thread_rng();
@@ -158,10 +313,12 @@ impl QuizController {
     }
 
     pub fn points(&self, user_state: &UserState) -> u32 {
+        let quiz = self.quiz.load();
+
         user_state
             .answers
             .iter()
-            .filter_map(|(quiz_name, answers)| self.quiz.get(quiz_name).map(|quiz| (quiz, answers)))
+            .filter_map(|(quiz_name, answers)| quiz.get(quiz_name).map(|quiz| (quiz, answers)))
             .map(|(quiz, answers)| {
                 let correct_answers = answers.iter().filter(|&&a| a).count();
                 (quiz.points * correct_answers as u32) / quiz.questions.len() as u32
@@ -174,20 +331,25 @@ impl QuizController {
                 .sum::<u32>()
     }
 
+    pub async fn total_points(&self) -> Result<u32> {
+        self.user_store.total_points().await
+    }
+
     pub async fn register_user(
         &self,
         codes: &[impl AsRef<str>],
         email: &str,
         consent: bool,
         user_state: &UserState,
-    ) -> u32 {
+    ) -> Result<u32> {
         let points = self.points(user_state);
         let now = Utc::now();
 
+        let code_map = self.codes.load();
         let codes = codes.into_iter().map(|code| code.as_ref()).collect::<BTreeSet<_>>();
         let codes = codes
             .iter()
-            .filter_map(|code| self.codes.get(&code.trim().to_lowercase()))
+            .filter_map(|code| code_map.get(&code.trim().to_lowercase()))
             .filter(|code| code.valid_from <= now && code.valid_to >= now)
             .collect::<Vec<_>>();
 
@@ -211,45 +373,113 @@ impl QuizController {
                 time: now,
             };
 
-            let user_writer = self.user_writer.clone();
-            let blocking_task = tokio::task::spawn_blocking(move || {
-                user_writer.write(record).unwrap();
-            });
-            blocking_task.await.unwrap();
-
-            points
-        } else {
-            points
+            // A `false` result just means another request already registered
+            // this user first; `points` is still the correct idempotent reply.
+            self.user_store.write(record).await?;
         }
+
+        Ok(points)
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct UserWriter {
-    writer: Arc<Mutex<csv::Writer<File>>>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Quiz, Wheel};
 
-impl UserWriter {
-    pub fn new(path: impl AsRef<Path>) -> Result<UserWriter> {
-        let file = OpenOptions::new().create(true).append(true).open(path)?;
+    #[derive(Clone, Debug)]
+    struct NullUserStore;
 
-        let writer = csv::WriterBuilder::new()
-            .has_headers(false)
-            .from_writer(file);
+    #[async_trait::async_trait]
+    impl UserStore for NullUserStore {
+        async fn write(&self, _record: UserRecord) -> Result<bool> {
+            Ok(true)
+        }
 
-        let writer = Arc::new(Mutex::new(writer));
+        async fn total_points(&self) -> Result<u32> {
+            Ok(0)
+        }
 
-        Ok(UserWriter { writer })
+        async fn exists(&self, _user_id: &str) -> Result<bool> {
+            Ok(false)
+        }
     }
 
-    pub fn write(&self, record: UserRecord) -> Result<()> {
-        let mut writer = self
-            .writer
-            .lock()
-            .map_err(|_err| anyhow!("couldn't lock writer"))?;
-        writer.serialize(record)?;
-        writer.flush()?;
+    fn test_controller(token_ttl_seconds: i64) -> QuizController {
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "test".to_string(),
+            hmac::Key::new(hmac::HMAC_SHA256, b"test-secret"),
+        );
 
-        Ok(())
+        QuizController::new(
+            "test".to_string(),
+            keys,
+            std::iter::empty::<&Quiz>(),
+            std::iter::empty::<&Code>(),
+            std::iter::empty::<&Wheel>(),
+            Arc::new(NullUserStore),
+            token_ttl_seconds,
+            true,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn encode_decode_user_round_trips() {
+        let controller = test_controller(3600);
+        let user_state = controller.create_user();
+
+        let token = controller.encode_user(&user_state).unwrap();
+        let decoded = controller.decode_user(&token).unwrap();
+
+        assert_eq!(decoded.id.0, user_state.id.0);
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let controller = test_controller(-1);
+        let user_state = controller.create_user();
+
+        let token = controller.encode_user(&user_state).unwrap();
+
+        assert!(controller.decode_user(&token).is_err());
+    }
+
+    #[test]
+    fn reload_with_duplicate_quiz_name_keeps_old_config() {
+        let controller = test_controller(3600);
+
+        let quiz_a = Quiz {
+            name: "a".into(),
+            points: 1,
+            questions: Vec::new(),
+        };
+        controller
+            .reload(&Config {
+                code: Vec::new(),
+                quiz: vec![quiz_a],
+                wheel: Vec::new(),
+            })
+            .unwrap();
+
+        let quiz_b1 = Quiz {
+            name: "b".into(),
+            points: 1,
+            questions: Vec::new(),
+        };
+        let quiz_b2 = Quiz {
+            name: "b".into(),
+            points: 2,
+            questions: Vec::new(),
+        };
+        let result = controller.reload(&Config {
+            code: Vec::new(),
+            quiz: vec![quiz_b1, quiz_b2],
+            wheel: Vec::new(),
+        });
+
+        assert!(result.is_err());
+        assert!(controller.quiz.load().contains_key("a"));
     }
 }
@@ -1,9 +1,10 @@
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 use rand::prelude::*;
 use ring::{digest, hmac};
 use serde::{Deserialize, Serialize};
-use std::{env, net::SocketAddr};
-use tokio::fs;
+use std::{collections::BTreeMap, env, net::SocketAddr, sync::Arc};
+use tokio::{fs, signal};
+use validator::{Validate, ValidationError, ValidationErrors};
 use warp::{
     http::{self, Response},
     reject,
@@ -11,12 +12,14 @@ use warp::{
     Filter,
 };
 
-use controllers::{QuizController, UserWriter};
+use controllers::QuizController;
 use models::{Config, UserState};
+use store::{SqlUserStore, UserStore, UserWriter};
 
 mod controllers;
 mod filters;
 mod models;
+mod store;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct QuizQuestionReply<'a> {
@@ -25,8 +28,9 @@ struct QuizQuestionReply<'a> {
     token: &'a str,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
 struct QuizAnswerRequest {
+    #[validate(length(min = 1, max = 256))]
     answer: String,
 }
 
@@ -43,13 +47,42 @@ struct WheelSpinReply<'a> {
     token: &'a str,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
 struct CheckoutRequest {
+    #[validate(length(max = 20), custom = "validate_codes")]
     codes: Vec<String>,
+
+    #[validate(email)]
     email: String,
+
+    #[validate(custom = "validate_consent")]
     consent: bool,
 }
 
+fn validate_codes(codes: &[String]) -> Result<(), ValidationError> {
+    let valid = codes.iter().all(|code| {
+        !code.is_empty()
+            && code.len() <= 64
+            && code
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("invalid_code"))
+    }
+}
+
+fn validate_consent(consent: &bool) -> Result<(), ValidationError> {
+    if *consent {
+        Ok(())
+    } else {
+        Err(ValidationError::new("consent_required"))
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct CheckoutReply {
     points: u32,
@@ -68,6 +101,37 @@ struct ErrorReply {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 enum ErrorCode {
     NotFound,
+    Validation(BTreeMap<String, Vec<String>>),
+    Internal,
+}
+
+fn internal_error_reply() -> impl Reply {
+    let reply = ErrorReply {
+        error: ErrorCode::Internal,
+    };
+
+    reply::with_status(reply::json(&reply), http::StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn validation_error_reply(errors: ValidationErrors) -> impl Reply {
+    let fields = errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let messages = errors
+                .iter()
+                .map(|error| error.code.to_string())
+                .collect::<Vec<_>>();
+
+            (field.to_string(), messages)
+        })
+        .collect();
+
+    let reply = ErrorReply {
+        error: ErrorCode::Validation(fields),
+    };
+
+    reply::with_status(reply::json(&reply), http::StatusCode::BAD_REQUEST)
 }
 
 #[tokio::main]
@@ -77,37 +141,126 @@ async fn main() -> Result<()> {
 
     let cors_origin = env::var("CORS_ORIGIN").unwrap_or_else(|_err| "http://localhost:1313".into());
 
-    let secret_key = env::var("SECRET_KEY")
-        .map_err(|err| Error::new(err))
-        .and_then(|env| {
-            let mut secret_key = [0u8; digest::SHA256_OUTPUT_LEN];
-            hex::decode_to_slice(env, &mut secret_key)?;
-            Ok(secret_key)
-        })
-        .or_else(|_err| -> Result<_> {
-            let mut secret_key = [0u8; digest::SHA256_OUTPUT_LEN];
-            rand::rngs::OsRng.fill(&mut secret_key);
+    let (primary_key_id, keys) = if let Ok(secret_keys) = env::var("SECRET_KEYS") {
+        let mut keys = BTreeMap::new();
+        let mut primary_key_id = None;
+
+        for entry in secret_keys.split(',') {
+            let mut parts = entry.splitn(2, '=');
+            let id = parts
+                .next()
+                .ok_or_else(|| anyhow!("bad SECRET_KEYS entry: {}", entry))?;
+            let hex_key = parts
+                .next()
+                .ok_or_else(|| anyhow!("bad SECRET_KEYS entry: {}", entry))?;
+
+            let mut key_bytes = [0u8; digest::SHA256_OUTPUT_LEN];
+            hex::decode_to_slice(hex_key, &mut key_bytes)?;
 
-            println!("No secret key was specified, generated a new secret key.");
-            println!("Rerun with SECRET_KEY={}", hex::encode(secret_key));
+            if primary_key_id.is_none() {
+                primary_key_id = Some(id.to_string());
+            }
+
+            keys.insert(id.to_string(), hmac::Key::new(hmac::HMAC_SHA256, &key_bytes));
+        }
+
+        let primary_key_id = primary_key_id.ok_or_else(|| anyhow!("SECRET_KEYS must not be empty"))?;
+
+        (primary_key_id, keys)
+    } else {
+        let secret_key = env::var("SECRET_KEY")
+            .map_err(|err| Error::new(err))
+            .and_then(|env| {
+                let mut secret_key = [0u8; digest::SHA256_OUTPUT_LEN];
+                hex::decode_to_slice(env, &mut secret_key)?;
+                Ok(secret_key)
+            })
+            .or_else(|_err| -> Result<_> {
+                let mut secret_key = [0u8; digest::SHA256_OUTPUT_LEN];
+                rand::rngs::OsRng.fill(&mut secret_key);
+
+                println!("No secret key was specified, generated a new secret key.");
+                println!("Rerun with SECRET_KEY={}", hex::encode(secret_key));
+
+                Ok(secret_key)
+            })?;
+
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "default".to_string(),
+            hmac::Key::new(hmac::HMAC_SHA256, secret_key.as_ref()),
+        );
 
-            Ok(secret_key)
-        })?;
+        ("default".to_string(), keys)
+    };
 
-    let secret_key = hmac::Key::new(hmac::HMAC_SHA256, secret_key.as_ref());
+    let token_ttl_seconds = env::var("TOKEN_TTL_SECONDS")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(3600);
+
+    let legacy_tokens_enabled = env::var("LEGACY_TOKENS")
+        .ok()
+        .and_then(|enabled| enabled.parse().ok())
+        .unwrap_or(true);
 
     let config = fs::read_to_string("quiz.toml").await?;
     let config: Config = toml::de::from_str(&config)?;
 
-    let user_writer = UserWriter::new("users.csv")?;
+    let user_store: Arc<dyn UserStore> = if let Ok(database_url) = env::var("DATABASE_URL") {
+        Arc::new(SqlUserStore::new(&database_url).await?)
+    } else {
+        Arc::new(UserWriter::new("users.csv")?)
+    };
 
     let quiz_controller = QuizController::new(
-        secret_key,
+        primary_key_id,
+        keys,
         config.quiz.iter(),
         config.code.iter(),
         config.wheel.iter(),
-        user_writer,
-    );
+        user_store,
+        token_ttl_seconds,
+        legacy_tokens_enabled,
+    )?;
+
+    {
+        let quiz_controller = quiz_controller.clone();
+        tokio::spawn(async move {
+            let mut sighup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+                Ok(sighup) => sighup,
+                Err(err) => {
+                    eprintln!("failed to install SIGHUP handler: {}", err);
+                    return;
+                }
+            };
+
+            loop {
+                sighup.recv().await;
+
+                let config = match fs::read_to_string("quiz.toml").await {
+                    Ok(config) => config,
+                    Err(err) => {
+                        eprintln!("failed to reload quiz.toml: {}", err);
+                        continue;
+                    }
+                };
+
+                let config: Config = match toml::de::from_str(&config) {
+                    Ok(config) => config,
+                    Err(err) => {
+                        eprintln!("failed to reload quiz.toml: {}", err);
+                        continue;
+                    }
+                };
+
+                match quiz_controller.reload(&config) {
+                    Ok(()) => println!("reloaded quiz.toml"),
+                    Err(err) => eprintln!("failed to reload quiz.toml: {}", err),
+                }
+            }
+        });
+    }
 
     let get_quiz = warp::path!("quiz" / String)
         .and(warp::get())
@@ -160,6 +313,10 @@ async fn main() -> Result<()> {
              body: QuizAnswerRequest,
              mut user_state: UserState,
              quiz_controller: QuizController| {
+                if let Err(errors) = body.validate() {
+                    return validation_error_reply(errors).into_response();
+                }
+
                 let answer =
                     quiz_controller.answer_question(&quiz_name, &mut user_state, &body.answer);
 
@@ -221,15 +378,20 @@ async fn main() -> Result<()> {
         .and(warp::get())
         .and(filters::user_state(quiz_controller.clone()))
         .and(filters::with_quiz_controller(quiz_controller.clone()))
-        .map(|user_state: UserState, quiz_controller: QuizController| {
-            let points = quiz_controller.points(&user_state);
-
-            let reply = StatsReply {
-                total_points: points,
-            };
+        .and_then(
+            |_user_state: UserState, quiz_controller: QuizController| async move {
+                match quiz_controller.total_points().await {
+                    Ok(points) => {
+                        let reply = StatsReply {
+                            total_points: points,
+                        };
 
-            reply::json(&reply).into_response()
-        });
+                        Ok::<_, reject::Rejection>(reply::json(&reply).into_response())
+                    }
+                    Err(_err) => Ok(internal_error_reply().into_response()),
+                }
+            },
+        );
 
     let checkout = warp::path!("checkout")
         .and(warp::post())
@@ -237,19 +399,17 @@ async fn main() -> Result<()> {
         .and(filters::user_state(quiz_controller.clone()))
         .and(filters::with_quiz_controller(quiz_controller.clone()))
         .and_then(|body: CheckoutRequest, user_state: UserState, quiz_controller: QuizController| async move {
-            if !body.email.contains('@') || body.email.len() < 3 {
-                let reply = ErrorReply { error: ErrorCode::NotFound };
-
-                Ok(reply::with_status(
-                        reply::json(&reply),
-                        http::StatusCode::NOT_FOUND,
-                    ).into_response())
-            } else {
-                let points = quiz_controller.register_user(&body.codes, &body.email, body.consent, &user_state).await;
+            if let Err(errors) = body.validate() {
+                return Ok::<_, reject::Rejection>(validation_error_reply(errors).into_response());
+            }
 
-                let reply = CheckoutReply { points };
+            match quiz_controller.register_user(&body.codes, &body.email, body.consent, &user_state).await {
+                Ok(points) => {
+                    let reply = CheckoutReply { points };
 
-                Ok::<_, reject::Rejection>(reply::json(&reply).into_response())
+                    Ok::<_, reject::Rejection>(reply::json(&reply).into_response())
+                }
+                Err(_err) => Ok(internal_error_reply().into_response()),
             }
         });
 
@@ -280,3 +440,32 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkout_request_rejects_invalid_email() {
+        let request = CheckoutRequest {
+            codes: Vec::new(),
+            email: "not-an-email".into(),
+            consent: true,
+        };
+
+        let errors = request.validate().unwrap_err();
+        assert!(errors.field_errors().contains_key("email"));
+    }
+
+    #[test]
+    fn checkout_request_requires_consent() {
+        let request = CheckoutRequest {
+            codes: Vec::new(),
+            email: "user@example.com".into(),
+            consent: false,
+        };
+
+        let errors = request.validate().unwrap_err();
+        assert!(errors.field_errors().contains_key("consent"));
+    }
+}
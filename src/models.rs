@@ -51,11 +51,12 @@ pub struct UserState {
     pub wheels: BTreeMap<String, u8>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct UserRecord {
     pub id: String,
     pub email: String,
     pub points: u32,
     pub codes: String,
+    pub consent: bool,
     pub time: DateTime<Utc>,
 }
@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use csv;
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::models::UserRecord;
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    // Returns `true` if `record` was inserted, `false` if a record for
+    // `record.id` already existed and this call was a no-op.
+    async fn write(&self, record: UserRecord) -> Result<bool>;
+
+    async fn total_points(&self) -> Result<u32>;
+
+    async fn exists(&self, user_id: &str) -> Result<bool>;
+}
+
+#[derive(Clone, Debug)]
+pub struct UserWriter {
+    path: Arc<PathBuf>,
+    writer: Arc<Mutex<csv::Writer<File>>>,
+}
+
+impl UserWriter {
+    pub fn new(path: impl AsRef<Path>) -> Result<UserWriter> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(file);
+
+        Ok(UserWriter {
+            path: Arc::new(path),
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    fn write_sync(&self, record: UserRecord) -> Result<bool> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_err| anyhow!("couldn't lock writer"))?;
+
+        if self.exists_sync(&record.id)? {
+            return Ok(false);
+        }
+
+        writer.serialize(record)?;
+        writer.flush()?;
+
+        Ok(true)
+    }
+
+    fn reader(&self) -> Result<csv::Reader<File>> {
+        let file = File::open(self.path.as_ref())?;
+        Ok(csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(file))
+    }
+
+    fn total_points_sync(&self) -> Result<u32> {
+        let mut reader = self.reader()?;
+        let total_points = reader
+            .deserialize::<UserRecord>()
+            .filter_map(|record| record.ok())
+            .map(|record| record.points)
+            .sum();
+
+        Ok(total_points)
+    }
+
+    fn exists_sync(&self, user_id: &str) -> Result<bool> {
+        let mut reader = self.reader()?;
+        let exists = reader
+            .deserialize::<UserRecord>()
+            .filter_map(|record| record.ok())
+            .any(|record| record.id == user_id);
+
+        Ok(exists)
+    }
+}
+
+#[async_trait]
+impl UserStore for UserWriter {
+    async fn write(&self, record: UserRecord) -> Result<bool> {
+        let writer = self.clone();
+        tokio::task::spawn_blocking(move || writer.write_sync(record)).await?
+    }
+
+    async fn total_points(&self) -> Result<u32> {
+        let writer = self.clone();
+        tokio::task::spawn_blocking(move || writer.total_points_sync()).await?
+    }
+
+    async fn exists(&self, user_id: &str) -> Result<bool> {
+        let writer = self.clone();
+        let user_id = user_id.to_string();
+        tokio::task::spawn_blocking(move || writer.exists_sync(&user_id)).await?
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SqlUserStore {
+    pool: sqlx::AnyPool,
+}
+
+impl SqlUserStore {
+    pub async fn new(database_url: &str) -> Result<SqlUserStore> {
+        let pool = sqlx::any::AnyPoolOptions::new()
+            .max_connections(5)
+            .connect(database_url)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS users (
+                id TEXT PRIMARY KEY,
+                email TEXT NOT NULL,
+                points INTEGER NOT NULL,
+                codes TEXT NOT NULL,
+                consent BOOLEAN NOT NULL,
+                time TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(SqlUserStore { pool })
+    }
+}
+
+#[async_trait]
+impl UserStore for SqlUserStore {
+    async fn write(&self, record: UserRecord) -> Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO users (id, email, points, codes, consent, time) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT(id) DO NOTHING",
+        )
+        .bind(record.id)
+        .bind(record.email)
+        .bind(record.points as i64)
+        .bind(record.codes)
+        .bind(record.consent)
+        .bind(record.time.to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn total_points(&self) -> Result<u32> {
+        let (total_points,): (i64,) = sqlx::query_as("SELECT COALESCE(SUM(points), 0) FROM users")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(total_points as u32)
+    }
+
+    async fn exists(&self, user_id: &str) -> Result<bool> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count > 0)
+    }
+}